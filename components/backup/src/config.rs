@@ -0,0 +1,49 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+use external_storage_export::MultipartConfig;
+use serde::{Deserialize, Serialize};
+use tikv_util::config::ReadableSize;
+
+use crate::checksum::ChecksumAlgorithm;
+
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    pub num_threads: usize,
+    pub batch_size: usize,
+    pub sst_max_size: ReadableSize,
+    pub enable_auto_tune: bool,
+    /// Part size, concurrency and retry budget for uploading large SSTs via
+    /// multipart upload rather than as a single object.
+    pub multipart: MultipartConfig,
+    /// Checksum algorithm used to protect newly written backup files.
+    /// Existing backups keep whatever algorithm they were written with,
+    /// recorded alongside their digest in the manifest.
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// When enabled, SSTs are content-defined-chunked and deduplicated
+    /// against chunks already present in the destination storage instead of
+    /// being uploaded whole every backup.
+    pub enable_dedup: bool,
+    /// When enabled, the key derived from the operator-supplied passphrase
+    /// only wraps a random master key, and that master key is the
+    /// data-encryption key; when disabled, the derived key is used directly.
+    pub enable_envelope_encryption: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let default_batch_size = 8;
+        Self {
+            // use at most 75% of vCPU by default
+            num_threads: (num_cpus::get() as f64 * 0.75).clamp(1.0, 32.0) as usize,
+            batch_size: default_batch_size,
+            sst_max_size: ReadableSize::mb(144),
+            enable_auto_tune: true,
+            multipart: MultipartConfig::default(),
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            enable_dedup: false,
+            enable_envelope_encryption: false,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Content-defined chunking and deduplication for incremental backups.
+//!
+//! Rather than re-uploading a whole SST on every backup, the byte stream is
+//! split into variable-length chunks at content-aligned boundaries, so an
+//! insertion only perturbs the chunks immediately around it rather than
+//! cascading through the rest of the file. Each chunk is stored under its
+//! own digest; a backup that has already uploaded a chunk with that digest
+//! (from this SST, an earlier backup, or an overlapping range) skips it. A
+//! small per-SST [`ChunkIndex`] lists the ordered chunk digests so that
+//! restore can reassemble the file and GC can tell which chunks are still
+//! referenced.
+
+use std::collections::{HashMap, HashSet};
+
+use external_storage_export::{ExternalStorage, UnpinReader};
+use futures::io::Cursor;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::errors::Result;
+
+/// Width of the rolling window the chunker hashes over.
+const WINDOW_SIZE: usize = 64;
+/// Chunks smaller than this are never cut, to bound the number of tiny
+/// objects a pathological input could produce.
+const MIN_CHUNK_SIZE: usize = 2 * 1024;
+/// A boundary is forced at this size even if the rolling hash never lands on
+/// a cut point, to bound the largest single chunk.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+/// `k = 13` targets an average chunk size of ~8 KiB: a boundary is declared
+/// whenever the low 13 bits of the rolling hash are all zero, which happens
+/// on average every `2^13` bytes for a well-mixed hash.
+const BOUNDARY_MASK: u64 = (1 << 13) - 1;
+
+/// Buzhash-style rolling hash over a fixed-size window, used to find
+/// content-defined chunk boundaries.
+///
+/// Boundaries are determined purely by the last `WINDOW_SIZE` bytes seen, so
+/// they are content-aligned rather than offset-aligned: inserting a byte
+/// shifts where later boundaries fall but does not change the chunking of
+/// data the insertion didn't touch.
+struct RollingHash {
+    window: [u8; WINDOW_SIZE],
+    pos: usize,
+    filled: usize,
+    hash: u64,
+}
+
+/// A pseudo-random table mapping each byte value to a 64-bit word, used to
+/// mix bytes into the rolling hash. Declared `const` so the chunker has no
+/// setup cost.
+const GEAR_TABLE: [u64; 256] = build_gear_table();
+
+const fn build_gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    // A simple splitmix64-style constant spread; only needs to look random
+    // enough to avoid pathological runs of repeated bytes hashing to the
+    // same value, not to be cryptographically secure.
+    while i < 256 {
+        let x = (i as u64).wrapping_mul(0x9E3779B97F4A7C15).wrapping_add(0xD1B54A32D192ED03);
+        table[i] = x ^ (x >> 32);
+        i += 1;
+    }
+    table
+}
+
+impl RollingHash {
+    fn new() -> Self {
+        Self {
+            window: [0; WINDOW_SIZE],
+            pos: 0,
+            filled: 0,
+            hash: 0,
+        }
+    }
+
+    /// Feeds one byte, rotating it into the window and updating the hash.
+    fn push(&mut self, byte: u8) -> u64 {
+        // Whether `self.window[self.pos]` already held a real, previously
+        // pushed byte before this call -- i.e. whether the window was
+        // already full going in. On the push that first fills the window
+        // (the 64th byte), `outgoing` is still the zero-initialized
+        // placeholder and must not be un-mixed: doing so bakes a
+        // `GEAR_TABLE[0]` phase term into the hash that depends on absolute
+        // byte offset rather than window content, breaking content-aligned
+        // chunk boundaries for the rest of the file.
+        let was_full = self.filled == WINDOW_SIZE;
+        let outgoing = self.window[self.pos];
+        self.window[self.pos] = byte;
+        self.pos = (self.pos + 1) % WINDOW_SIZE;
+        self.filled = (self.filled + 1).min(WINDOW_SIZE);
+        self.hash = self.hash.rotate_left(1) ^ GEAR_TABLE[byte as usize];
+        if was_full {
+            // Once the window is full, un-mix the byte that just fell out so
+            // the hash only ever reflects the last WINDOW_SIZE bytes.
+            self.hash ^= GEAR_TABLE[outgoing as usize].rotate_left(WINDOW_SIZE as u32 % 64);
+        }
+        self.hash
+    }
+}
+
+/// Splits `data` into content-defined chunks.
+///
+/// Boundaries are declared whenever the rolling hash's low bits are all
+/// zero, subject to [`MIN_CHUNK_SIZE`] and [`MAX_CHUNK_SIZE`].
+pub fn split_chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut hasher = RollingHash::new();
+    let mut chunks = Vec::new();
+    let mut start = 0usize;
+    for (i, &byte) in data.iter().enumerate() {
+        let hash = hasher.push(byte);
+        let len = i - start + 1;
+        if len < MIN_CHUNK_SIZE {
+            continue;
+        }
+        if len >= MAX_CHUNK_SIZE || hash & BOUNDARY_MASK == 0 {
+            chunks.push(&data[start..=i]);
+            start = i + 1;
+        }
+    }
+    if start < data.len() {
+        chunks.push(&data[start..]);
+    }
+    chunks
+}
+
+/// Content address for a chunk: its object name in storage.
+pub fn chunk_digest(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+/// Per-SST index listing the ordered chunk digests that reconstruct it.
+/// This, not the raw SST, is what [`crate::verify`] and restore consult.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct ChunkIndex {
+    pub chunk_digests: Vec<String>,
+    pub total_len: u64,
+    pub crc64xor: u64,
+}
+
+/// Splits `data` into chunks, building its [`ChunkIndex`] and returning the
+/// chunk bytes keyed by digest for upload.
+pub fn build_index(data: &[u8]) -> (ChunkIndex, Vec<(String, &[u8])>) {
+    let mut digest64 = crc64fast::Digest::new();
+    digest64.write(data);
+    let mut chunk_digests = Vec::new();
+    let mut keyed = Vec::new();
+    for chunk in split_chunks(data) {
+        let digest = chunk_digest(chunk);
+        chunk_digests.push(digest.clone());
+        keyed.push((digest, chunk));
+    }
+    (
+        ChunkIndex {
+            chunk_digests,
+            total_len: data.len() as u64,
+            crc64xor: digest64.sum64(),
+        },
+        keyed,
+    )
+}
+
+/// The object name prefix chunks are stored under, content-addressed by
+/// their digest.
+pub fn chunk_object_name(digest: &str) -> String {
+    format!("chunks/{}", digest)
+}
+
+/// Uploads every chunk in `keyed` that isn't already present in
+/// `known_chunks`, adding newly uploaded digests to `known_chunks` so a
+/// later call in the same backup (or an overlapping SST) skips them too.
+/// Returns the number of chunks that were actually uploaded.
+pub async fn store_chunks(
+    storage: &dyn ExternalStorage,
+    keyed: &[(String, &[u8])],
+    known_chunks: &mut HashSet<String>,
+) -> Result<usize> {
+    let mut uploaded = 0;
+    for (digest, chunk) in keyed {
+        if known_chunks.contains(digest) {
+            continue;
+        }
+        let reader = UnpinReader(Box::new(Cursor::new(chunk.to_vec())));
+        storage
+            .write(&chunk_object_name(digest), reader, chunk.len() as u64)
+            .await?;
+        known_chunks.insert(digest.clone());
+        uploaded += 1;
+    }
+    Ok(uploaded)
+}
+
+/// Serializes `index` and writes it to `storage` under `name` (typically
+/// obtained from [`crate::manifest::ManifestFileEntry::chunk_index_name`]).
+pub async fn write_index(
+    storage: &dyn ExternalStorage,
+    name: &str,
+    index: &ChunkIndex,
+) -> Result<()> {
+    let content = serde_json::to_vec(index).map_err(|e| crate::Error::Other(e.into()))?;
+    let len = content.len() as u64;
+    let reader = UnpinReader(Box::new(Cursor::new(content)));
+    storage.write(name, reader, len).await?;
+    Ok(())
+}
+
+/// Reads back a [`ChunkIndex`] previously written by [`write_index`].
+pub async fn read_index(storage: &dyn ExternalStorage, name: &str) -> Result<ChunkIndex> {
+    use futures::AsyncReadExt;
+
+    let mut reader = storage.read(name);
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content).await?;
+    serde_json::from_slice(&content).map_err(|e| crate::Error::Other(e.into()))
+}
+
+/// Reconstructs the original SST bytes from a [`ChunkIndex`] by reading and
+/// concatenating its chunks in order.
+pub async fn restore_from_index(
+    storage: &dyn ExternalStorage,
+    index: &ChunkIndex,
+) -> Result<Vec<u8>> {
+    use futures::AsyncReadExt;
+
+    let mut out = Vec::with_capacity(index.total_len as usize);
+    for digest in &index.chunk_digests {
+        let mut reader = storage.read(&chunk_object_name(digest));
+        reader.read_to_end(&mut out).await?;
+    }
+    Ok(out)
+}
+
+/// Counts, across a set of [`ChunkIndex`]es still referenced by live
+/// backups, how many times each chunk digest is used. A chunk with a count
+/// of zero is safe for [`find_unreferenced`] to report for deletion.
+pub fn count_references(indexes: &[ChunkIndex]) -> HashMap<String, u64> {
+    let mut counts = HashMap::new();
+    for index in indexes {
+        for digest in &index.chunk_digests {
+            *counts.entry(digest.clone()).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+/// Mark-and-sweep: given every chunk digest currently stored and the
+/// reference counts computed from the backups still being kept, returns the
+/// digests that are no longer referenced and can be deleted.
+pub fn find_unreferenced(
+    all_chunks: impl IntoIterator<Item = String>,
+    live_refs: &HashMap<String, u64>,
+) -> Vec<String> {
+    all_chunks
+        .into_iter()
+        .filter(|digest| !live_refs.contains_key(digest))
+        .collect()
+}
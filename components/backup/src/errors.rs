@@ -0,0 +1,32 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+use std::io::Error as IoError;
+
+use error_code::{self, ErrorCode, ErrorCodeExt};
+use quick_error::quick_error;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: IoError) {
+            from()
+            display("io error {}", err)
+        }
+        Other(err: Box<dyn std::error::Error + Sync + Send>) {
+            from()
+            cause(err.as_ref())
+            display("{}", err)
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl ErrorCodeExt for Error {
+    fn error_code(&self) -> ErrorCode {
+        match self {
+            Error::Io(_) => error_code::backup::IO,
+            Error::Other(_) => error_code::backup::UNKNOWN,
+        }
+    }
+}
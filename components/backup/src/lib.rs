@@ -0,0 +1,28 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+mod checksum;
+mod config;
+mod dedup;
+mod encryption;
+mod errors;
+mod manifest;
+mod prune;
+mod verify;
+
+pub use checksum::{checksum, ChecksumAlgorithm, ChecksumHasher};
+pub use config::Config;
+pub use dedup::{
+    build_index, chunk_digest, chunk_object_name, count_references, find_unreferenced,
+    read_index, restore_from_index, split_chunks, store_chunks, write_index, ChunkIndex,
+};
+pub use encryption::{new_key, recover_key, KdfParams, KeyCheckRecord};
+pub use errors::{Error, Result};
+pub use manifest::{
+    read_manifest, read_manifest_at, write_manifest, write_manifest_at, Manifest,
+    ManifestFileEntry, MANIFEST_FILE_NAME,
+};
+pub use prune::{
+    discover_backups, execute_prune, plan_prune, run_prune, BackupEntry, PruneAction,
+    PruneDecision, RetentionPolicy,
+};
+pub use verify::{verify, verify_manifest, verify_storage, FileVerifyResult, VerifyReport};
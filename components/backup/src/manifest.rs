@@ -0,0 +1,165 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A self-describing manifest written at the end of every backup.
+//!
+//! Historically the only way to find out what a backup contains was to list
+//! the external storage directory and guess the column family of each file
+//! from its name. The manifest
+//! records everything needed to reconstruct an `SstMeta` or `DownloadRequest`
+//! for a file directly, so restore and verification no longer need to parse
+//! file names or list the bucket to enumerate a backup's contents.
+
+use external_storage_export::{ExternalStorage, UnpinReader};
+use futures::{io::Cursor, AsyncReadExt};
+use kvproto::brpb::File;
+use serde::{Deserialize, Serialize};
+
+use crate::{checksum::ChecksumAlgorithm, encryption::KeyCheckRecord, errors::Result};
+
+/// The well-known object name every backup writes its manifest under.
+pub const MANIFEST_FILE_NAME: &str = "backupmeta.manifest.json";
+
+/// Manifest entry for a single produced SST file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManifestFileEntry {
+    pub name: String,
+    pub cf: String,
+    pub start_key: Vec<u8>,
+    pub end_key: Vec<u8>,
+    pub total_bytes: u64,
+    pub total_kvs: u64,
+    pub crc64xor: u64,
+    pub start_version: u64,
+    pub end_version: u64,
+    /// The algorithm used to compute `checksum`. Defaults to `Crc32` so that
+    /// manifests written before this field existed still parse, in which
+    /// case `crc64xor` remains the checksum of record.
+    #[serde(default)]
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// Hex-encoded digest of the file under `checksum_algorithm`. Absent for
+    /// manifests written before stronger checksums were supported.
+    #[serde(default)]
+    pub checksum: Option<String>,
+    /// Present when the file was stored as deduplicated, content-defined
+    /// chunks rather than as a single object; names the chunk index object
+    /// that lists its constituent chunk digests.
+    #[serde(default)]
+    pub chunk_index_name: Option<String>,
+}
+
+impl ManifestFileEntry {
+    pub fn from_file(file: &File) -> Self {
+        Self {
+            name: file.get_name().to_owned(),
+            cf: file.get_cf().to_owned(),
+            start_key: file.get_start_key().to_vec(),
+            end_key: file.get_end_key().to_vec(),
+            total_bytes: file.get_size(),
+            total_kvs: file.get_total_kvs(),
+            crc64xor: file.get_crc64xor(),
+            start_version: file.get_start_version(),
+            end_version: file.get_end_version(),
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            checksum: None,
+            chunk_index_name: None,
+        }
+    }
+
+    /// Attaches a digest computed with a stronger algorithm than the
+    /// embedded `crc64xor`, e.g. CRC32C or SHA-256.
+    pub fn with_checksum(mut self, algorithm: ChecksumAlgorithm, digest: String) -> Self {
+        self.checksum_algorithm = algorithm;
+        self.checksum = Some(digest);
+        self
+    }
+
+    /// Marks this file as stored via deduplicated chunks, pointing at the
+    /// chunk index object that lists them.
+    pub fn with_chunk_index(mut self, index_name: String) -> Self {
+        self.chunk_index_name = Some(index_name);
+        self
+    }
+
+    /// Reconstructs the `File` that was used to produce this entry, so
+    /// callers that only have the manifest can still build an `SstMeta` or
+    /// `DownloadRequest` without re-deriving fields from the file name.
+    pub fn to_file(&self) -> File {
+        let mut file = File::default();
+        file.set_name(self.name.clone());
+        file.set_cf(self.cf.clone());
+        file.set_start_key(self.start_key.clone());
+        file.set_end_key(self.end_key.clone());
+        file.set_size(self.total_bytes);
+        file.set_total_kvs(self.total_kvs);
+        file.set_crc64xor(self.crc64xor);
+        file.set_start_version(self.start_version);
+        file.set_end_version(self.end_version);
+        file
+    }
+}
+
+/// The top-level manifest describing one backup.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct Manifest {
+    /// The overall key range covered by this backup.
+    pub start_key: Vec<u8>,
+    pub end_key: Vec<u8>,
+    /// The TS at which the backup was taken.
+    pub backup_ts: u64,
+    pub files: Vec<ManifestFileEntry>,
+    /// Present when the backup is encrypted with a passphrase-derived key;
+    /// lets restore verify the passphrase before decrypting any SST.
+    #[serde(default)]
+    pub key_check: Option<KeyCheckRecord>,
+}
+
+impl Manifest {
+    pub fn new(start_key: Vec<u8>, end_key: Vec<u8>, backup_ts: u64, files: &[File]) -> Self {
+        Self {
+            start_key,
+            end_key,
+            backup_ts,
+            files: files.iter().map(ManifestFileEntry::from_file).collect(),
+            key_check: None,
+        }
+    }
+
+    /// Records the key-check fingerprint for a passphrase-encrypted backup.
+    pub fn with_key_check(mut self, key_check: KeyCheckRecord) -> Self {
+        self.key_check = Some(key_check);
+        self
+    }
+}
+
+/// Serializes `manifest` and writes it to `storage` under `name`.
+pub async fn write_manifest_at(
+    storage: &dyn ExternalStorage,
+    name: &str,
+    manifest: &Manifest,
+) -> Result<()> {
+    let content = serde_json::to_vec_pretty(manifest).map_err(|e| crate::Error::Other(e.into()))?;
+    let len = content.len() as u64;
+    let reader = UnpinReader(Box::new(Cursor::new(content)));
+    storage.write(name, reader, len).await?;
+    Ok(())
+}
+
+/// Serializes `manifest` and writes it to `storage` under
+/// [`MANIFEST_FILE_NAME`].
+pub async fn write_manifest(storage: &dyn ExternalStorage, manifest: &Manifest) -> Result<()> {
+    write_manifest_at(storage, MANIFEST_FILE_NAME, manifest).await
+}
+
+/// Reads back the manifest previously written by [`write_manifest_at`]
+/// under `name`.
+pub async fn read_manifest_at(storage: &dyn ExternalStorage, name: &str) -> Result<Manifest> {
+    let mut reader = storage.read(name);
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content).await?;
+    serde_json::from_slice(&content).map_err(|e| crate::Error::Other(e.into()))
+}
+
+/// Reads back the manifest previously written by [`write_manifest`].
+pub async fn read_manifest(storage: &dyn ExternalStorage) -> Result<Manifest> {
+    read_manifest_at(storage, MANIFEST_FILE_NAME).await
+}
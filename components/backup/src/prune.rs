@@ -0,0 +1,541 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! GFS-style (grandfather-father-son) retention for backups.
+//!
+//! Nothing currently ages out old backups -- every run accumulates forever.
+//! [`discover_backups`] enumerates the backups actually present in storage
+//! rather than trusting a caller-supplied list, [`plan_prune`] buckets each
+//! one's TS into day/week/month buckets, keeps the newest backup in each
+//! retained bucket (plus the `keep_last` most recent backups outright,
+//! regardless of bucket), and reports everything else for removal.
+//! [`execute_prune`] deletes the files (and, when dedup is enabled, the
+//! chunks no surviving backup references). [`run_prune`] chains all three
+//! for the common case; a caller that only wants the decisions can call
+//! [`discover_backups`] and [`plan_prune`] directly instead and skip
+//! [`execute_prune`], which is what a dry run amounts to.
+
+use std::collections::HashSet;
+
+use external_storage_export::ExternalStorage;
+
+use crate::{
+    dedup::{chunk_object_name, count_references, find_unreferenced, read_index},
+    errors::Result,
+    manifest::{read_manifest_at, Manifest, MANIFEST_FILE_NAME},
+};
+
+/// Joins a backup's storage path prefix with an object name living under
+/// it (its SSTs, manifest, and chunk index files, unlike deduplicated chunk
+/// content itself, which lives in a path-independent, backup-spanning
+/// namespace -- see `chunk_object_name`).
+fn object_path(path: &str, name: &str) -> String {
+    format!("{}/{}", path.trim_end_matches('/'), name)
+}
+
+/// Keep-N / keep-daily / keep-weekly / keep-monthly retention policy, the
+/// same shape as restic/borg's GFS rotation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+/// One backup as seen by the pruner.
+#[derive(Debug, Clone)]
+pub struct BackupEntry {
+    /// The storage path (prefix) this backup's files and manifest live
+    /// under.
+    pub path: String,
+    pub backup_ts: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PruneDecision {
+    Keep,
+    Remove,
+}
+
+#[derive(Debug, Clone)]
+pub struct PruneAction {
+    pub path: String,
+    pub backup_ts: u64,
+    pub decision: PruneDecision,
+    /// Which retention bucket kept this backup, or why it was removed.
+    pub reason: &'static str,
+}
+
+/// A `backup_ts` is a TiKV timestamp: the physical time in milliseconds is
+/// packed into the high 46 bits, ahead of a logical counter in the low 18.
+fn physical_millis(ts: u64) -> u64 {
+    ts >> 18
+}
+
+fn day_bucket(ts: u64) -> i64 {
+    (physical_millis(ts) / (24 * 3600 * 1000)) as i64
+}
+
+fn week_bucket(ts: u64) -> i64 {
+    day_bucket(ts) / 7
+}
+
+fn month_bucket(ts: u64) -> i64 {
+    // Approximate 30-day months: exact enough to decide what to keep,
+    // unlike billing.
+    day_bucket(ts) / 30
+}
+
+/// Plans which backups to keep/remove under `policy`. `entries` need not be
+/// pre-sorted; the result is ordered newest-first. This performs no I/O, so
+/// it doubles as the implementation of a dry run: callers that only want to
+/// see the plan can stop here instead of calling [`execute_prune`].
+pub fn plan_prune(mut entries: Vec<BackupEntry>, policy: &RetentionPolicy) -> Vec<PruneAction> {
+    entries.sort_by(|a, b| b.backup_ts.cmp(&a.backup_ts));
+
+    let mut kept_days = HashSet::new();
+    let mut kept_weeks = HashSet::new();
+    let mut kept_months = HashSet::new();
+    let mut actions = Vec::with_capacity(entries.len());
+
+    for (i, entry) in entries.into_iter().enumerate() {
+        if i < policy.keep_last {
+            actions.push(PruneAction {
+                path: entry.path,
+                backup_ts: entry.backup_ts,
+                decision: PruneDecision::Keep,
+                reason: "keep-last",
+            });
+            continue;
+        }
+
+        let day = day_bucket(entry.backup_ts);
+        let week = week_bucket(entry.backup_ts);
+        let month = month_bucket(entry.backup_ts);
+
+        let reason = if !kept_days.contains(&day) && kept_days.len() < policy.keep_daily {
+            kept_days.insert(day);
+            Some("keep-daily")
+        } else if !kept_weeks.contains(&week) && kept_weeks.len() < policy.keep_weekly {
+            kept_weeks.insert(week);
+            Some("keep-weekly")
+        } else if !kept_months.contains(&month) && kept_months.len() < policy.keep_monthly {
+            kept_months.insert(month);
+            Some("keep-monthly")
+        } else {
+            None
+        };
+
+        actions.push(match reason {
+            Some(reason) => PruneAction {
+                path: entry.path,
+                backup_ts: entry.backup_ts,
+                decision: PruneDecision::Keep,
+                reason,
+            },
+            None => PruneAction {
+                path: entry.path,
+                backup_ts: entry.backup_ts,
+                decision: PruneDecision::Remove,
+                reason: "expired",
+            },
+        });
+    }
+    actions
+}
+
+/// Deletes every object belonging to a removed backup -- its SSTs, its
+/// chunk index files (if any), and its own manifest -- as recorded by its
+/// manifest, all addressed under that backup's `path`. `kept` must pair the
+/// path and manifest of every backup [`plan_prune`] decided to keep, so that
+/// deduplicated chunks still referenced by a surviving backup are not
+/// collected out from under it.
+///
+/// When `dry_run` is `true`, nothing is deleted: the plan has already been
+/// computed by [`plan_prune`], so a dry run is simply skipping the deletes
+/// below and letting the caller report `plan` as-is.
+pub async fn execute_prune(
+    storage: &dyn ExternalStorage,
+    plan: &[PruneAction],
+    removed: &[(String, Manifest)],
+    kept: &[(String, Manifest)],
+    dedup_enabled: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        return Ok(());
+    }
+
+    let mut all_chunks = Vec::new();
+    for action in plan.iter().filter(|a| a.decision == PruneDecision::Remove) {
+        if let Some((path, manifest)) = removed
+            .iter()
+            .find(|(path, m)| *path == action.path && m.backup_ts == action.backup_ts)
+        {
+            for file in &manifest.files {
+                storage.delete(&object_path(path, &file.name)).await?;
+                if let Some(index_name) = &file.chunk_index_name {
+                    if dedup_enabled {
+                        let index = read_index(storage, &object_path(path, index_name)).await?;
+                        all_chunks.extend(index.chunk_digests);
+                    }
+                    storage.delete(&object_path(path, index_name)).await?;
+                }
+            }
+            storage
+                .delete(&object_path(path, MANIFEST_FILE_NAME))
+                .await?;
+        }
+    }
+
+    if dedup_enabled {
+        let mut live_indexes = Vec::new();
+        for (path, manifest) in kept {
+            for index_name in manifest.files.iter().filter_map(|f| f.chunk_index_name.as_ref()) {
+                live_indexes.push(read_index(storage, &object_path(path, index_name)).await?);
+            }
+        }
+
+        let live_refs = count_references(&live_indexes);
+        for digest in find_unreferenced(all_chunks, &live_refs) {
+            storage.delete(&chunk_object_name(&digest)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Enumerates every backup present in `storage` by listing for manifest
+/// objects rather than requiring a caller to already maintain its own
+/// catalog of what backups exist. A backup's path is whatever directory its
+/// manifest was found under.
+pub async fn discover_backups(storage: &dyn ExternalStorage) -> Result<Vec<(String, Manifest)>> {
+    let suffix = format!("/{}", MANIFEST_FILE_NAME);
+    let mut backups = Vec::new();
+    for name in storage.list("").await? {
+        if let Some(path) = name.strip_suffix(suffix.as_str()) {
+            let manifest = read_manifest_at(storage, &name).await?;
+            backups.push((path.to_owned(), manifest));
+        }
+    }
+    Ok(backups)
+}
+
+/// Discovers every backup in `storage`, plans what to keep and remove under
+/// `policy`, and (unless `dry_run`) executes that plan -- the end-to-end
+/// entry point a caller running a scheduled prune should use. Callers that
+/// already maintain their own backup catalog, or want to inspect the plan
+/// before committing to it, can call [`plan_prune`] and [`execute_prune`]
+/// directly instead.
+pub async fn run_prune(
+    storage: &dyn ExternalStorage,
+    policy: &RetentionPolicy,
+    dedup_enabled: bool,
+    dry_run: bool,
+) -> Result<Vec<PruneAction>> {
+    let backups = discover_backups(storage).await?;
+    let entries = backups
+        .iter()
+        .map(|(path, manifest)| BackupEntry {
+            path: path.clone(),
+            backup_ts: manifest.backup_ts,
+        })
+        .collect();
+    let plan = plan_prune(entries, policy);
+
+    let is_decided = |path: &str, backup_ts: u64, decision: PruneDecision| {
+        plan.iter()
+            .any(|a| a.path == path && a.backup_ts == backup_ts && a.decision == decision)
+    };
+    let removed: Vec<(String, Manifest)> = backups
+        .iter()
+        .filter(|(path, manifest)| is_decided(path, manifest.backup_ts, PruneDecision::Remove))
+        .cloned()
+        .collect();
+    let kept: Vec<(String, Manifest)> = backups
+        .into_iter()
+        .filter(|(path, manifest)| is_decided(path, manifest.backup_ts, PruneDecision::Keep))
+        .collect();
+
+    execute_prune(storage, &plan, &removed, &kept, dedup_enabled, dry_run).await?;
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts_for_day(day: u64) -> u64 {
+        (day * 24 * 3600 * 1000) << 18
+    }
+
+    fn entry(day: u64) -> BackupEntry {
+        BackupEntry {
+            path: format!("backup-day-{}", day),
+            backup_ts: ts_for_day(day),
+        }
+    }
+
+    fn decision_for(actions: &[PruneAction], day: u64) -> PruneDecision {
+        actions
+            .iter()
+            .find(|a| a.backup_ts == ts_for_day(day))
+            .unwrap()
+            .decision
+    }
+
+    #[test]
+    fn keeps_the_most_recent_keep_last_backups_regardless_of_bucket() {
+        let entries = (0..5).map(entry).collect();
+        let policy = RetentionPolicy {
+            keep_last: 3,
+            ..Default::default()
+        };
+        let actions = plan_prune(entries, &policy);
+        for day in 2..5 {
+            assert_eq!(decision_for(&actions, day), PruneDecision::Keep, "day {}", day);
+        }
+        for day in 0..2 {
+            assert_eq!(decision_for(&actions, day), PruneDecision::Remove, "day {}", day);
+        }
+    }
+
+    #[test]
+    fn keeps_one_backup_per_kept_day_bucket() {
+        // One backup each on three distinct days; keep_daily only has room
+        // for two of them.
+        let entries = vec![entry(0), entry(1), entry(2)];
+        let policy = RetentionPolicy {
+            keep_daily: 2,
+            ..Default::default()
+        };
+        let actions = plan_prune(entries, &policy);
+        // Newest-first: day 2 and day 1 fill the two keep-daily slots; day 0
+        // has no daily slot left and matches no weekly/monthly policy either.
+        assert_eq!(decision_for(&actions, 2), PruneDecision::Keep);
+        assert_eq!(decision_for(&actions, 1), PruneDecision::Keep);
+        assert_eq!(decision_for(&actions, 0), PruneDecision::Remove);
+    }
+
+    #[test]
+    fn falls_back_to_weekly_then_monthly_buckets() {
+        let entries = vec![entry(0), entry(8), entry(40)];
+        let policy = RetentionPolicy {
+            keep_daily: 1,
+            keep_weekly: 1,
+            keep_monthly: 1,
+            ..Default::default()
+        };
+        let actions = plan_prune(entries, &policy);
+        // day 40 is the newest in its own day/week/month bucket -> keep-daily.
+        assert_eq!(decision_for(&actions, 40), PruneDecision::Keep);
+        // day 8 is in a different week from day 40 -> keep-weekly.
+        assert_eq!(decision_for(&actions, 8), PruneDecision::Keep);
+        // day 0 shares month bucket 0 with day 8, but day 8 was kept by the
+        // weekly rule and did not claim that month -> keep-monthly.
+        assert_eq!(decision_for(&actions, 0), PruneDecision::Keep);
+    }
+
+    use std::{collections::HashMap, sync::Mutex};
+
+    use async_trait::async_trait;
+    use external_storage_export::UnpinReader;
+    use futures::{executor::block_on, AsyncRead, AsyncReadExt};
+
+    use crate::manifest::{write_manifest_at, ManifestFileEntry};
+
+    #[derive(Default)]
+    struct MemStorage {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl ExternalStorage for MemStorage {
+        async fn write(
+            &self,
+            name: &str,
+            mut reader: UnpinReader,
+            _len: u64,
+        ) -> std::io::Result<()> {
+            let mut buf = Vec::new();
+            reader.0.read_to_end(&mut buf).await?;
+            self.objects.lock().unwrap().insert(name.to_owned(), buf);
+            Ok(())
+        }
+
+        fn read(&self, name: &str) -> Box<dyn AsyncRead + Unpin + Send + '_> {
+            let content = self
+                .objects
+                .lock()
+                .unwrap()
+                .get(name)
+                .cloned()
+                .unwrap_or_default();
+            Box::new(futures::io::Cursor::new(content))
+        }
+
+        async fn delete(&self, name: &str) -> std::io::Result<()> {
+            self.objects.lock().unwrap().remove(name);
+            Ok(())
+        }
+
+        async fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+            let mut names: Vec<String> = self
+                .objects
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|k| k.starts_with(prefix))
+                .cloned()
+                .collect();
+            names.sort();
+            Ok(names)
+        }
+    }
+
+    fn one_file_manifest(backup_ts: u64, file_name: &str) -> Manifest {
+        Manifest {
+            start_key: vec![],
+            end_key: vec![255],
+            backup_ts,
+            files: vec![ManifestFileEntry {
+                name: file_name.to_owned(),
+                ..ManifestFileEntry::from_file(&Default::default())
+            }],
+            key_check: None,
+        }
+    }
+
+    #[test]
+    fn dry_run_deletes_nothing() {
+        let storage = MemStorage::default();
+        storage
+            .objects
+            .lock()
+            .unwrap()
+            .insert("backup-day-0/default.sst".to_owned(), vec![1, 2, 3]);
+        storage
+            .objects
+            .lock()
+            .unwrap()
+            .insert(format!("backup-day-0/{}", MANIFEST_FILE_NAME), vec![4]);
+
+        let manifest = one_file_manifest(ts_for_day(0), "default.sst");
+        let removed = vec![("backup-day-0".to_owned(), manifest.clone())];
+        let actions = vec![PruneAction {
+            path: "backup-day-0".to_owned(),
+            backup_ts: manifest.backup_ts,
+            decision: PruneDecision::Remove,
+            reason: "expired",
+        }];
+
+        block_on(execute_prune(
+            &storage, &actions, &removed, &[], false, true,
+        ))
+        .unwrap();
+
+        assert_eq!(storage.objects.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn deletes_removed_backups_files_and_manifest_under_their_own_path() {
+        let storage = MemStorage::default();
+        storage
+            .objects
+            .lock()
+            .unwrap()
+            .insert("backup-day-0/default.sst".to_owned(), vec![1, 2, 3]);
+        storage
+            .objects
+            .lock()
+            .unwrap()
+            .insert(format!("backup-day-0/{}", MANIFEST_FILE_NAME), vec![4]);
+        // A second, kept backup's file of the same name under its own path
+        // must survive.
+        storage
+            .objects
+            .lock()
+            .unwrap()
+            .insert("backup-day-1/default.sst".to_owned(), vec![9]);
+
+        let removed_manifest = one_file_manifest(ts_for_day(0), "default.sst");
+        let removed = vec![("backup-day-0".to_owned(), removed_manifest.clone())];
+        let kept = vec![(
+            "backup-day-1".to_owned(),
+            one_file_manifest(ts_for_day(1), "default.sst"),
+        )];
+        let actions = vec![PruneAction {
+            path: "backup-day-0".to_owned(),
+            backup_ts: removed_manifest.backup_ts,
+            decision: PruneDecision::Remove,
+            reason: "expired",
+        }];
+
+        block_on(execute_prune(
+            &storage, &actions, &removed, &kept, false, false,
+        ))
+        .unwrap();
+
+        let objects = storage.objects.lock().unwrap();
+        assert!(!objects.contains_key("backup-day-0/default.sst"));
+        assert!(!objects.contains_key(&format!("backup-day-0/{}", MANIFEST_FILE_NAME)));
+        assert!(objects.contains_key("backup-day-1/default.sst"));
+    }
+
+    #[test]
+    fn discovers_every_backup_by_its_manifest() {
+        let storage = MemStorage::default();
+        for day in [0, 1] {
+            let manifest = one_file_manifest(ts_for_day(day), "default.sst");
+            block_on(write_manifest_at(
+                &storage,
+                &format!("backup-day-{}/{}", day, MANIFEST_FILE_NAME),
+                &manifest,
+            ))
+            .unwrap();
+        }
+
+        let mut backups = block_on(discover_backups(&storage)).unwrap();
+        backups.sort_by_key(|(path, _)| path.clone());
+
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[0].0, "backup-day-0");
+        assert_eq!(backups[0].1.backup_ts, ts_for_day(0));
+        assert_eq!(backups[1].0, "backup-day-1");
+        assert_eq!(backups[1].1.backup_ts, ts_for_day(1));
+    }
+
+    #[test]
+    fn run_prune_discovers_and_removes_expired_backups() {
+        let storage = MemStorage::default();
+        for day in 0..3 {
+            let manifest = one_file_manifest(ts_for_day(day), "default.sst");
+            block_on(write_manifest_at(
+                &storage,
+                &format!("backup-day-{}/{}", day, MANIFEST_FILE_NAME),
+                &manifest,
+            ))
+            .unwrap();
+            storage
+                .objects
+                .lock()
+                .unwrap()
+                .insert(format!("backup-day-{}/default.sst", day), vec![day as u8]);
+        }
+
+        let policy = RetentionPolicy {
+            keep_last: 1,
+            ..Default::default()
+        };
+        let plan = block_on(run_prune(&storage, &policy, false, false)).unwrap();
+
+        assert_eq!(decision_for(&plan, 2), PruneDecision::Keep);
+        assert_eq!(decision_for(&plan, 1), PruneDecision::Remove);
+        assert_eq!(decision_for(&plan, 0), PruneDecision::Remove);
+
+        let objects = storage.objects.lock().unwrap();
+        assert!(objects.contains_key("backup-day-2/default.sst"));
+        assert!(!objects.contains_key("backup-day-0/default.sst"));
+        assert!(!objects.contains_key("backup-day-1/default.sst"));
+    }
+}
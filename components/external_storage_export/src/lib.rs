@@ -0,0 +1,40 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The external storage abstraction backup writes SSTs to and restore reads
+//! them back from.
+
+use std::io;
+
+use async_trait::async_trait;
+use futures::AsyncRead;
+
+mod multipart;
+
+pub use multipart::{multipart_write, MultipartConfig, MultipartExternalStorage};
+
+/// Wraps a boxed, unpin `AsyncRead` so it can be passed across the
+/// `ExternalStorage::write` boundary without forcing every caller to box
+/// and pin their own reader by hand.
+pub struct UnpinReader(pub Box<dyn AsyncRead + Unpin + Send>);
+
+/// A destination (and source) for backup SSTs: local disk, S3, GCS, etc.
+#[async_trait]
+pub trait ExternalStorage: 'static + Send + Sync {
+    /// Writes `reader`, which must yield exactly `content_length` bytes, to
+    /// the object named `name`.
+    async fn write(&self, name: &str, reader: UnpinReader, content_length: u64) -> io::Result<()>;
+
+    /// Returns a reader over the object named `name`.
+    fn read(&self, name: &str) -> Box<dyn AsyncRead + Unpin + Send + '_>;
+
+    /// Deletes the object named `name`, e.g. as part of pruning an expired
+    /// backup. Deleting a name that does not exist is not an error.
+    async fn delete(&self, name: &str) -> io::Result<()>;
+
+    /// Lists every object whose name starts with `prefix` (`""` lists
+    /// everything). This is what lets a caller discover what's actually in
+    /// storage -- which files exist, which backups are present -- rather
+    /// than trusting a list it was handed: see `backup::verify_storage` and
+    /// `backup::discover_backups`.
+    async fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+}
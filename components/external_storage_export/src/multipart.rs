@@ -0,0 +1,394 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Multipart, parallel, resumable uploads for large SST files.
+//!
+//! Writing a whole object in one `write` call means backup must buffer (or
+//! re-read) the entire SST if a single network error occurs partway
+//! through, and restore's single `read_to_end` stalls and wastes memory on
+//! multi-gigabyte files. Splitting an upload into fixed-size parts, pushing
+//! them through a bounded worker pool, and retrying only the parts that
+//! fail bounds peak memory to roughly `part_size * concurrency` regardless
+//! of the object's total size -- parts are produced lazily off the reader,
+//! one per free pool slot, rather than all being read up front.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::{io::Cursor, stream, AsyncRead, AsyncReadExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tikv_util::{config::ReadableSize, warn};
+
+use crate::{ExternalStorage, UnpinReader};
+
+/// Tunables for multipart upload, configured next to `sst_max_size` on the
+/// backup config.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct MultipartConfig {
+    pub part_size: ReadableSize,
+    pub concurrency: usize,
+    pub max_retries: u32,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        Self {
+            part_size: ReadableSize::mb(16),
+            concurrency: 4,
+            max_retries: 3,
+        }
+    }
+}
+
+/// A storage backend that supports committing an object from independently
+/// uploaded parts, e.g. S3's multipart upload API.
+///
+/// `dyn ExternalStorage` implements this below, backed by plain
+/// `write`/`read`/`delete` calls against per-part objects -- good enough for
+/// backends (like local disk) with no native multipart API. [`multipart_write`]
+/// always goes through that implementation, so a backend's own multipart API
+/// is not used by it.
+#[async_trait]
+pub trait MultipartExternalStorage: Send + Sync {
+    /// Starts a multipart upload for `name`, returning an opaque upload id.
+    async fn create_multipart(&self, name: &str) -> io::Result<String>;
+
+    /// Uploads one part (1-indexed, as most multipart APIs require) and
+    /// returns its ETag, to be passed to `complete_multipart`.
+    async fn upload_part(
+        &self,
+        name: &str,
+        upload_id: &str,
+        part_number: u32,
+        data: Vec<u8>,
+    ) -> io::Result<String>;
+
+    /// Commits the object from the given ordered `(part_number, etag, len)`
+    /// list; `len` is each part's exact byte length, known at upload time,
+    /// so a generic implementation can stream the concatenation out in one
+    /// pass instead of buffering it to learn its size.
+    async fn complete_multipart(
+        &self,
+        name: &str,
+        upload_id: &str,
+        parts: &[(u32, String, u64)],
+    ) -> io::Result<()>;
+
+    /// Releases any storage held by an upload that will not be completed.
+    async fn abort_multipart(&self, name: &str, upload_id: &str) -> io::Result<()>;
+}
+
+fn part_object_name(upload_id: &str, part_number: u32) -> String {
+    format!("{}.part{:05}", upload_id, part_number)
+}
+
+/// Reads a sequence of storage objects back to back as one stream, opening
+/// each one lazily only once the previous one is exhausted, so that
+/// finalizing a multipart upload never needs to hold more than one part in
+/// memory at a time.
+struct ChainedParts<'a> {
+    storage: &'a dyn ExternalStorage,
+    remaining: std::vec::IntoIter<String>,
+    current: Option<Box<dyn AsyncRead + Unpin + Send + 'a>>,
+}
+
+impl<'a> ChainedParts<'a> {
+    fn new(storage: &'a dyn ExternalStorage, names: Vec<String>) -> Self {
+        Self {
+            storage,
+            remaining: names.into_iter(),
+            current: None,
+        }
+    }
+}
+
+impl<'a> AsyncRead for ChainedParts<'a> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.current.is_none() {
+                match this.remaining.next() {
+                    Some(name) => this.current = Some(this.storage.read(&name)),
+                    None => return Poll::Ready(Ok(0)),
+                }
+            }
+            let reader = this.current.as_mut().unwrap();
+            match Pin::new(reader.as_mut()).poll_read(cx, buf) {
+                Poll::Ready(Ok(0)) => {
+                    this.current = None;
+                    continue;
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MultipartExternalStorage for dyn ExternalStorage {
+    async fn create_multipart(&self, name: &str) -> io::Result<String> {
+        // Local disk and most S3-compatible backends alike can key parts off
+        // the destination name itself; a backend with its own multipart API
+        // would override this to return the id that API hands back.
+        Ok(name.to_owned())
+    }
+
+    async fn upload_part(
+        &self,
+        _name: &str,
+        upload_id: &str,
+        part_number: u32,
+        data: Vec<u8>,
+    ) -> io::Result<String> {
+        let part_name = part_object_name(upload_id, part_number);
+        let len = data.len() as u64;
+        self.write(&part_name, UnpinReader(Box::new(Cursor::new(data))), len)
+            .await?;
+        Ok(part_name)
+    }
+
+    async fn complete_multipart(
+        &self,
+        name: &str,
+        _upload_id: &str,
+        parts: &[(u32, String, u64)],
+    ) -> io::Result<()> {
+        // There's no generic "compose objects server-side" primitive on
+        // `ExternalStorage`, so finalize by chaining each part's reader, in
+        // order, into one stream and passing that straight to `write` --
+        // each part is still only ever read part-size-at-a-time, the same
+        // bound `multipart_write` upholds on the way in.
+        let total_len = parts.iter().map(|(_, _, len)| *len).sum();
+        let part_names: Vec<String> = parts.iter().map(|(_, name, _)| name.clone()).collect();
+        let reader = ChainedParts::new(self, part_names);
+        self.write(name, UnpinReader(Box::new(reader)), total_len)
+            .await?;
+        for (_, part_name, _) in parts {
+            self.delete(part_name).await?;
+        }
+        Ok(())
+    }
+
+    async fn abort_multipart(&self, _name: &str, _upload_id: &str) -> io::Result<()> {
+        // Nothing but the part objects was persisted for this generic,
+        // read-back-based implementation, and they are not deleted here (nor
+        // by `multipart_write`), so a failed upload leaves them in storage.
+        Ok(())
+    }
+}
+
+/// Uploads `reader` to `name` as a multipart object: splits it into
+/// `config.part_size` parts, produced lazily so at most `config.concurrency`
+/// are ever buffered at once, uploads them concurrently, retries an
+/// individual failed part with exponential backoff (rather than restarting
+/// the whole object), and finalizes by committing the part list.
+pub async fn multipart_write(
+    storage: &dyn ExternalStorage,
+    name: &str,
+    reader: impl AsyncRead + Unpin + Send,
+    config: MultipartConfig,
+) -> io::Result<()> {
+    let multipart = storage;
+    let upload_id = multipart.create_multipart(name).await?;
+    let part_size = config.part_size.0 as usize;
+
+    // Lazily reads one part at a time off `reader`; `buffer_unordered` below
+    // only polls this stream for a new part when a pool slot frees up, so at
+    // most `concurrency` parts are ever resident in memory at once.
+    let parts = stream::unfold(Some(reader), move |state| async move {
+        let mut reader = state?;
+        let mut buf = vec![0u8; part_size];
+        let mut filled = 0;
+        while filled < part_size {
+            match reader.read(&mut buf[filled..]).await {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) => return Some((Err(e), None)),
+            }
+        }
+        if filled == 0 {
+            return None;
+        }
+        buf.truncate(filled);
+        let next_state = if filled < part_size { None } else { Some(reader) };
+        Some((Ok(buf), next_state))
+    });
+
+    let uploaded: Vec<io::Result<(u32, String, u64)>> = parts
+        .enumerate()
+        .map(|(i, part)| {
+            let upload_id = &upload_id;
+            async move {
+                let data = part?;
+                let len = data.len() as u64;
+                let (part_number, etag) = upload_part_with_retry(
+                    multipart,
+                    name,
+                    upload_id,
+                    (i + 1) as u32,
+                    data,
+                    config.max_retries,
+                )
+                .await?;
+                Ok((part_number, etag, len))
+            }
+        })
+        .buffer_unordered(config.concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut committed = Vec::with_capacity(uploaded.len());
+    for result in uploaded {
+        match result {
+            Ok(part) => committed.push(part),
+            Err(e) => {
+                let _ = multipart.abort_multipart(name, &upload_id).await;
+                return Err(e);
+            }
+        }
+    }
+    committed.sort_by_key(|(number, _, _)| *number);
+
+    multipart.complete_multipart(name, &upload_id, &committed).await
+}
+
+async fn upload_part_with_retry(
+    storage: &dyn ExternalStorage,
+    name: &str,
+    upload_id: &str,
+    part_number: u32,
+    data: Vec<u8>,
+    max_retries: u32,
+) -> io::Result<(u32, String)> {
+    let mut attempt = 0;
+    loop {
+        match storage
+            .upload_part(name, upload_id, part_number, data.clone())
+            .await
+        {
+            Ok(etag) => return Ok((part_number, etag)),
+            Err(e) if attempt < max_retries => {
+                attempt += 1;
+                warn!(
+                    "retrying multipart upload part";
+                    "name" => name,
+                    "part_number" => part_number,
+                    "attempt" => attempt,
+                    "err" => %e,
+                );
+                let backoff = Duration::from_millis(100 * 2u64.pow(attempt));
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use futures::{executor::block_on, io::Cursor};
+
+    use super::*;
+
+    /// An in-memory `ExternalStorage` good enough to exercise
+    /// `multipart_write` end to end without any real backend.
+    #[derive(Default)]
+    struct MemStorage {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl ExternalStorage for MemStorage {
+        async fn write(&self, name: &str, mut reader: UnpinReader, _len: u64) -> io::Result<()> {
+            let mut buf = Vec::new();
+            reader.0.read_to_end(&mut buf).await?;
+            self.objects.lock().unwrap().insert(name.to_owned(), buf);
+            Ok(())
+        }
+
+        fn read(&self, name: &str) -> Box<dyn AsyncRead + Unpin + Send + '_> {
+            let content = self
+                .objects
+                .lock()
+                .unwrap()
+                .get(name)
+                .cloned()
+                .unwrap_or_default();
+            Box::new(Cursor::new(content))
+        }
+
+        async fn delete(&self, name: &str) -> io::Result<()> {
+            self.objects.lock().unwrap().remove(name);
+            Ok(())
+        }
+
+        async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+            let mut names: Vec<String> = self
+                .objects
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|k| k.starts_with(prefix))
+                .cloned()
+                .collect();
+            names.sort();
+            Ok(names)
+        }
+    }
+
+    #[test]
+    fn reassembles_content_from_many_parts() {
+        let storage = MemStorage::default();
+        let data = vec![7u8; 10 * 1024];
+        let config = MultipartConfig {
+            part_size: ReadableSize::kb(1),
+            concurrency: 3,
+            max_retries: 1,
+        };
+        block_on(multipart_write(
+            &storage as &dyn ExternalStorage,
+            "big.sst",
+            Cursor::new(data.clone()),
+            config,
+        ))
+        .unwrap();
+
+        let objects = storage.objects.lock().unwrap();
+        assert_eq!(objects.get("big.sst"), Some(&data));
+        // Part objects should have been cleaned up by `complete_multipart`.
+        assert!(objects.keys().all(|k| k == "big.sst"));
+    }
+
+    #[test]
+    fn small_input_is_a_single_part() {
+        let storage = MemStorage::default();
+        let data = vec![1, 2, 3, 4, 5];
+        block_on(multipart_write(
+            &storage as &dyn ExternalStorage,
+            "small.sst",
+            Cursor::new(data.clone()),
+            MultipartConfig::default(),
+        ))
+        .unwrap();
+        assert_eq!(
+            storage.objects.lock().unwrap().get("small.sst"),
+            Some(&data)
+        );
+    }
+
+    #[test]
+    fn part_object_names_are_stable_and_ordered() {
+        assert_eq!(part_object_name("up", 1), "up.part00001");
+        assert_eq!(part_object_name("up", 2), "up.part00002");
+    }
+}
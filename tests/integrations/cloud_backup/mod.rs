@@ -1,13 +1,21 @@
 // Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
 
 use std::{
-    cmp, fs,
+    cmp,
+    collections::HashSet,
+    fs,
     path::{Path, PathBuf},
     time::Duration,
 };
 
-use engine_traits::{CfName, CF_DEFAULT, CF_WRITE};
-use external_storage_export::{create_storage, make_local_backend};
+use backup::{
+    build_index, checksum, new_key, read_manifest, recover_key, restore_from_index, run_prune,
+    store_chunks, verify, verify_manifest, write_index, write_manifest, write_manifest_at,
+    ChecksumAlgorithm, Manifest, ManifestFileEntry, PruneDecision, RetentionPolicy,
+    MANIFEST_FILE_NAME,
+};
+use engine_traits::CF_WRITE;
+use external_storage_export::{create_storage, make_local_backend, multipart_write, MultipartConfig};
 use file_system::calc_crc32_bytes;
 use futures::{executor::block_on, AsyncReadExt, StreamExt};
 use kvproto::{
@@ -97,6 +105,23 @@ fn test_backup_and_import() {
     // Only leader can handle backup.
     assert!(!resps1.is_empty());
     assert!(!resps1[0].get_files().is_empty());
+
+    // The freshly written SSTs should verify clean without restoring them.
+    let backend = make_local_backend(&storage_path);
+    let storage = create_storage(&backend, Default::default()).unwrap();
+    for resp in &resps1 {
+        let report = block_on(verify(storage.as_ref(), resp.get_files())).unwrap();
+        assert!(report.all_ok(), "{:?}", report.failures().collect::<Vec<_>>());
+    }
+
+    // Write a manifest enumerating this backup's files so that restore does
+    // not need to list the bucket or guess the CF from each file's name.
+    let mut backup_files = vec![];
+    for resp in &resps1 {
+        backup_files.extend_from_slice(resp.get_files());
+    }
+    let manifest = Manifest::new(vec![], vec![255], backup_ts.into_inner(), &backup_files);
+    block_on(write_manifest(storage.as_ref(), &manifest)).unwrap();
     cluster1.stop();
 
     // // Use importer to restore backup files.
@@ -106,25 +131,26 @@ fn test_backup_and_import() {
     let backend = make_local_backend(&storage_path);
     let storage = create_storage(&backend, Default::default()).unwrap();
     let context = cluster2.new_rpc_context(b"");
+    let manifest = block_on(read_manifest(storage.as_ref())).unwrap();
     let mut metas = vec![];
-    for resp in &resps1 {
-        let mut sst_meta = SstMeta::default();
-        sst_meta.region_id = context.get_region_id();
-        sst_meta.set_region_epoch(context.get_region_epoch().clone());
-        sst_meta.set_uuid(uuid::Uuid::new_v4().as_bytes().to_vec());
-        for f in resp.get_files() {
-            let mut reader = storage.read(&f.name);
-            let mut content = vec![];
-            block_on(reader.read_to_end(&mut content)).unwrap();
-            let mut m = sst_meta.clone();
-            m.crc32 = calc_crc32_bytes(&content);
-            m.length = content.len() as _;
-            m.cf_name = name_to_cf(&f.name).to_owned();
-            m.mut_range().set_start(f.get_start_key().to_vec());
-            m.mut_range().set_end(f.get_end_key().to_vec());
-            let name = f.get_name().to_string();
-            metas.push((m, name));
-        }
+    for entry in &manifest.files {
+        // Reconstruct the `File` the manifest was built from instead of
+        // reaching into the entry's fields directly, so restore and backup
+        // agree on what a manifest entry means.
+        let file = entry.to_file();
+        let mut reader = storage.read(file.get_name());
+        let mut content = vec![];
+        block_on(reader.read_to_end(&mut content)).unwrap();
+        let mut m = SstMeta::default();
+        m.region_id = context.get_region_id();
+        m.set_region_epoch(context.get_region_epoch().clone());
+        m.set_uuid(uuid::Uuid::new_v4().as_bytes().to_vec());
+        m.crc32 = calc_crc32_bytes(&content);
+        m.length = file.get_size();
+        m.cf_name = file.get_cf().to_owned();
+        m.mut_range().set_start(file.get_start_key().to_vec());
+        m.mut_range().set_end(file.get_end_key().to_vec());
+        metas.push((m, file.get_name().to_owned()));
     }
     for store_id in cluster2.get_stores() {
         let channel = cluster2.get_client_channel(store_id);
@@ -171,6 +197,194 @@ fn test_backup_and_import() {
     cluster2.stop();
 }
 
+#[test]
+fn test_manifest_checksum_algorithm_is_honored_on_verify() {
+    let tmp = Builder::new().tempdir().unwrap();
+    let storage_path = make_unique_dir(tmp.path());
+    let backend = make_local_backend(&storage_path);
+    let storage = create_storage(&backend, Default::default()).unwrap();
+
+    // A file stored under the stronger SHA-256 algorithm rather than the
+    // default CRC32, exercising the manifest's per-file checksum_algorithm
+    // rather than always falling back to crc64xor.
+    let content = b"checksum-algorithm-integration-test".to_vec();
+    let digest = checksum(ChecksumAlgorithm::Sha256, &content);
+    block_on(storage.write(
+        "default.sst",
+        external_storage_export::UnpinReader(Box::new(futures::io::Cursor::new(content.clone()))),
+        content.len() as u64,
+    ))
+    .unwrap();
+
+    let entry = ManifestFileEntry {
+        name: "default.sst".to_owned(),
+        total_bytes: content.len() as u64,
+        ..ManifestFileEntry::from_file(&Default::default())
+    }
+    .with_checksum(ChecksumAlgorithm::Sha256, digest);
+    let manifest = Manifest {
+        start_key: vec![],
+        end_key: vec![255],
+        backup_ts: 1,
+        files: vec![entry],
+        key_check: None,
+    };
+
+    let report = block_on(verify_manifest(storage.as_ref(), &manifest)).unwrap();
+    assert!(report.all_ok(), "{:?}", report.failures().collect::<Vec<_>>());
+
+    // Corrupting the stored bytes must be caught by the SHA-256 digest, not
+    // just the (unset) legacy crc64xor field.
+    block_on(storage.write(
+        "default.sst",
+        external_storage_export::UnpinReader(Box::new(futures::io::Cursor::new(b"corrupted".to_vec()))),
+        9,
+    ))
+    .unwrap();
+    let report = block_on(verify_manifest(storage.as_ref(), &manifest)).unwrap();
+    assert!(!report.all_ok());
+}
+
+#[test]
+fn test_dedup_skips_already_uploaded_chunks_and_restores_identical_bytes() {
+    let tmp = Builder::new().tempdir().unwrap();
+    let storage_path = make_unique_dir(tmp.path());
+    let backend = make_local_backend(&storage_path);
+    let storage = create_storage(&backend, Default::default()).unwrap();
+
+    // Large enough, and repetitive enough, to reliably produce more than one
+    // content-defined chunk.
+    let first_sst: Vec<u8> = (0..200_000u32).flat_map(|i| i.to_le_bytes()).collect();
+    let (first_index, first_keyed) = build_index(&first_sst);
+    let mut known_chunks = HashSet::new();
+    let first_uploaded =
+        block_on(store_chunks(storage.as_ref(), &first_keyed, &mut known_chunks)).unwrap();
+    assert_eq!(first_uploaded, first_index.chunk_digests.len());
+    block_on(write_index(storage.as_ref(), "first.sst.chunks", &first_index)).unwrap();
+
+    // A second "backup" that only appends a small suffix to the first SST:
+    // every chunk up to the appended tail is byte-identical, so it should be
+    // skipped rather than re-uploaded.
+    let mut second_sst = first_sst.clone();
+    second_sst.extend_from_slice(b"appended-in-the-second-backup");
+    let (second_index, second_keyed) = build_index(&second_sst);
+    let second_uploaded =
+        block_on(store_chunks(storage.as_ref(), &second_keyed, &mut known_chunks)).unwrap();
+    assert!(
+        second_uploaded < second_index.chunk_digests.len(),
+        "expected at least one chunk to be deduplicated against the first backup"
+    );
+    block_on(write_index(
+        storage.as_ref(),
+        "second.sst.chunks",
+        &second_index,
+    ))
+    .unwrap();
+
+    assert_eq!(
+        block_on(restore_from_index(storage.as_ref(), &first_index)).unwrap(),
+        first_sst
+    );
+    assert_eq!(
+        block_on(restore_from_index(storage.as_ref(), &second_index)).unwrap(),
+        second_sst
+    );
+}
+
+#[test]
+fn test_envelope_encryption_key_round_trips_through_the_manifest() {
+    let tmp = Builder::new().tempdir().unwrap();
+    let storage_path = make_unique_dir(tmp.path());
+    let backend = make_local_backend(&storage_path);
+    let storage = create_storage(&backend, Default::default()).unwrap();
+
+    // enable_envelope_encryption: true -- the passphrase only unwraps a
+    // random master key, so the data-encryption key returned here is that
+    // master key, not the passphrase-derived key itself.
+    let (data_key, key_check) = new_key(b"correct horse battery staple", true).unwrap();
+    let manifest = Manifest::new(vec![], vec![255], 1, &[]).with_key_check(key_check);
+    block_on(write_manifest(storage.as_ref(), &manifest)).unwrap();
+
+    let restored_manifest = block_on(read_manifest(storage.as_ref())).unwrap();
+    let key_check = restored_manifest.key_check.expect("manifest should carry a key_check record");
+    assert_eq!(
+        recover_key(b"correct horse battery staple", &key_check).unwrap(),
+        data_key
+    );
+    assert!(recover_key(b"wrong passphrase", &key_check).is_err());
+}
+
+#[test]
+fn test_multipart_write_reassembles_a_large_sst_on_real_storage() {
+    let tmp = Builder::new().tempdir().unwrap();
+    let storage_path = make_unique_dir(tmp.path());
+    let backend = make_local_backend(&storage_path);
+    let storage = create_storage(&backend, Default::default()).unwrap();
+
+    // Several times the configured part size, so `multipart_write` actually
+    // splits this into multiple parts.
+    let config = MultipartConfig {
+        part_size: ReadableSize::kb(64),
+        ..Default::default()
+    };
+    let data: Vec<u8> = (0..500_000u32).map(|i| i as u8).collect();
+    block_on(multipart_write(
+        storage.as_ref(),
+        "multipart.sst",
+        futures::io::Cursor::new(data.clone()),
+        config,
+    ))
+    .unwrap();
+
+    let mut restored = vec![];
+    block_on(storage.read("multipart.sst").read_to_end(&mut restored)).unwrap();
+    assert_eq!(restored, data);
+}
+
+#[test]
+fn test_run_prune_discovers_backups_from_storage_and_removes_the_expired_ones() {
+    let tmp = Builder::new().tempdir().unwrap();
+    let storage_path = make_unique_dir(tmp.path());
+    let backend = make_local_backend(&storage_path);
+    let storage = create_storage(&backend, Default::default()).unwrap();
+
+    // Three independent backups, each under its own path, none of which
+    // `run_prune` is handed directly -- it must discover them by listing
+    // `storage` for manifest objects.
+    for (path, backup_ts) in [("backup-0", 100), ("backup-1", 200), ("backup-2", 300)] {
+        let manifest = Manifest::new(vec![], vec![255], backup_ts, &[]);
+        block_on(write_manifest_at(
+            storage.as_ref(),
+            &format!("{}/{}", path, MANIFEST_FILE_NAME),
+            &manifest,
+        ))
+        .unwrap();
+    }
+
+    let policy = RetentionPolicy {
+        keep_last: 1,
+        ..Default::default()
+    };
+    let plan = block_on(run_prune(storage.as_ref(), &policy, false, false)).unwrap();
+
+    let decision_for = |backup_ts: u64| {
+        plan.iter()
+            .find(|action| action.backup_ts == backup_ts)
+            .unwrap()
+            .decision
+    };
+    assert_eq!(decision_for(300), PruneDecision::Keep);
+    assert_eq!(decision_for(200), PruneDecision::Remove);
+    assert_eq!(decision_for(100), PruneDecision::Remove);
+
+    assert!(block_on(storage.list("backup-2/")).unwrap().contains(&format!(
+        "backup-2/{}",
+        MANIFEST_FILE_NAME
+    )));
+    assert!(block_on(storage.list("backup-0/")).unwrap().is_empty());
+    assert!(block_on(storage.list("backup-1/")).unwrap().is_empty());
+}
+
 // Retry if encounter error
 macro_rules! retry_req {
     ($call_req: expr, $check_resp: expr, $resp:ident, $retry:literal, $timeout:literal) => {
@@ -310,17 +524,6 @@ pub fn backup(
     resps
 }
 
-// Extract CF name from sst name.
-pub fn name_to_cf(name: &str) -> CfName {
-    if name.contains(CF_DEFAULT) {
-        CF_DEFAULT
-    } else if name.contains(CF_WRITE) {
-        CF_WRITE
-    } else {
-        unreachable!()
-    }
-}
-
 pub fn make_unique_dir(path: &Path) -> PathBuf {
     let uid: u64 = rand::thread_rng().gen();
     let tmp_suffix = format!("{:016x}", uid);